@@ -1,17 +1,18 @@
 use anyhow::{bail, Context};
-use chrono::{Datelike, NaiveDateTime};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
 use clap::Parser;
 use dropbox_sdk::{
     default_client::UserAuthDefaultClient,
     files::{self, DeleteArg, ListFolderArg},
 };
 use std::{
+    collections::{HashMap, HashSet},
     fs::File,
     io::{BufRead, BufReader},
     num::ParseIntError,
     path::PathBuf,
     str::FromStr,
-    time::SystemTime, rc::Rc,
+    rc::Rc,
 };
 
 #[derive(Debug, Parser)]
@@ -27,6 +28,46 @@ struct Args {
 
     #[clap(long)]
     fs_path: Option<String>,
+
+    /// Proceed even if the configured retention would keep zero backups in
+    /// some group (see `group_key`).
+    #[clap(long)]
+    allow_empty: bool,
+
+    /// Only consider backups whose name starts with this prefix.
+    #[clap(long)]
+    prefix: Option<String>,
+
+    /// Print a per-backup keep/remove table for the configured policy and
+    /// exit without contacting Dropbox's delete API.
+    #[clap(long)]
+    simulate: bool,
+
+    #[clap(flatten)]
+    keep: KeepOptions,
+}
+
+/// How many backups to keep per retention category, following the
+/// restic/proxmox-backup "keep last/hourly/daily/weekly/monthly/yearly" model.
+#[derive(Debug, Clone, Copy, Default, clap::Args)]
+struct KeepOptions {
+    #[clap(long)]
+    keep_last: Option<u32>,
+
+    #[clap(long)]
+    keep_hourly: Option<u32>,
+
+    #[clap(long)]
+    keep_daily: Option<u32>,
+
+    #[clap(long)]
+    keep_weekly: Option<u32>,
+
+    #[clap(long)]
+    keep_monthly: Option<u32>,
+
+    #[clap(long)]
+    keep_yearly: Option<u32>,
 }
 
 trait BackupReader {
@@ -51,19 +92,27 @@ struct DropboxBackupReader {
 impl BackupReader for DropboxBackupReader {
     fn read(&self) -> Result<Vec<Backup>, BackupReadError> {
         println!("Querying {} ...", self.list_path);
-        let list_folder_result = files::list_folder(
+        let mut list_folder_result = files::list_folder(
             self.client.as_ref(),
             &ListFolderArg::new(self.list_path.to_string()),
         )
         .map_err(|o| format!("dbx read: {}", o))?
         .map_err(|o| format!("list: {}", o))?;
         println!("Querying {} ... done", self.list_path);
-        if list_folder_result.has_more {
-            // list_folder_result.cursor
-            Err("need to handle more values with cursor!".to_string())?
+        let mut entries = list_folder_result.entries;
+        while list_folder_result.has_more {
+            println!("Querying {} (continue) ...", self.list_path);
+            list_folder_result = files::list_folder_continue(
+                self.client.as_ref(),
+                &files::ListFolderContinueArg::new(list_folder_result.cursor),
+            )
+            .map_err(|o| format!("dbx read continue: {}", o))?
+            .map_err(|o| format!("list continue: {}", o))?;
+            println!("Querying {} (continue) ... done", self.list_path);
+            entries.extend(list_folder_result.entries);
         }
-        let mut backups = Vec::with_capacity(list_folder_result.entries.len());
-        for entry in list_folder_result.entries {
+        let mut backups = Vec::with_capacity(entries.len());
+        for entry in entries {
             if let files::Metadata::File(metadata) = entry {
                 let backup_result: Result<Date, _> = metadata.name.parse();
                 if let Ok(backup_date) = backup_result {
@@ -201,39 +250,30 @@ fn main() -> anyhow::Result<()> {
         }
     };
     let mut backups = backup_reader.read().context("read backups")?;
-    let mut years: Vec<Year> = Vec::new();
-    for (i, backup) in backups.iter().enumerate() {
-        let year = years.iter_mut().find(|o| o.num == backup.date.year);
-        let year: &mut Year = if let Some(year) = year {
-            year
-        } else {
-            let year = Year::new(backup.date.year);
-            years.push(year);
-            let idx = years.len() - 1;
-            &mut years[idx]
-        };
-        let mut month = &mut year.months[(backup.date.month - 1) as usize];
-        month.days[(backup.date.day - 1) as usize] = Some(Day::new(i as u32));
-    }
-    let seconds_since_epoch = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap();
-    let seconds_since_epoch: i64 = seconds_since_epoch.as_secs().try_into().unwrap();
-    let date_time = NaiveDateTime::from_timestamp(seconds_since_epoch, 0);
-    let date = date_time.date();
-    let cur = Date::new(date.year() as u32, date.month(), date.day());
-    println!("today's date: {}/{}/{}", cur.year, cur.month, cur.day);
-    let mut days_to_remove = Vec::new();
-    for year in years.iter() {
-        for (month_idx, month) in year.months.iter().enumerate() {
-            let policy = policy_for(cur, year.num, month_idx as u32 + 1);
-            let mut to_remove = apply_policy(policy, month);
-            days_to_remove.append(&mut to_remove);
+    if let Some(prefix) = &args.prefix {
+        backups.retain(|backup| backup.name.starts_with(prefix.as_str()));
+    }
+    let results = compute_prune_marks_grouped(&backups, &args.keep);
+    if args.simulate {
+        print_simulation(&backups, &results);
+        return Ok(());
+    }
+    if !args.allow_empty {
+        let mut keeps_something: HashMap<&str, bool> = HashMap::new();
+        for result in results.values() {
+            let keeps = keeps_something.entry(result.group.as_str()).or_insert(false);
+            *keeps |= matches!(result.mark, PruneMark::Keep(_));
+        }
+        if let Some((empty_group, _)) = keeps_something.iter().find(|(_, keeps)| !**keeps) {
+            bail!(
+                "configured retention keeps no backups in group {:?}; pass --allow-empty to proceed anyway",
+                empty_group
+            );
         }
     }
-    let removals: Vec<Removal> = days_to_remove
-        .into_iter()
-        .map(|o| Removal(std::mem::take(&mut backups[o.idx as usize].name)))
+    let removals: Vec<Removal> = (0..backups.len())
+        .filter(|idx| !matches!(results.get(idx).map(|r| r.mark), Some(PruneMark::Keep(_))))
+        .map(|idx| Removal(std::mem::take(&mut backups[idx].name)))
         .collect();
     if args.dry_run.unwrap_or_default() {
         for removal in removals {
@@ -250,83 +290,176 @@ fn main() -> anyhow::Result<()> {
 
 struct Removal(String);
 
-fn apply_policy(policy: MonthPolicy, month: &Month) -> Vec<Day> {
-    match policy {
-        MonthPolicy::Daily => Vec::new(),
-        MonthPolicy::Weekly => keep_days(month, &[1, 8, 15, 22, 29]),
-        MonthPolicy::BiMonthly => keep_days(month, &[1, 15]),
-        MonthPolicy::First => keep_days(month, &[1]),
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PruneMark {
+    /// Kept, and by which category (last/hourly/daily/weekly/monthly/yearly).
+    Keep(&'static str),
+    Remove,
 }
 
-fn keep_days(month: &Month, day_nums_to_keep: &[u32]) -> Vec<Day> {
-    let mut result: Vec<Day> = Vec::with_capacity(31 - day_nums_to_keep.len());
-    let mut day_nums_to_keep = day_nums_to_keep.iter();
-    let mut next_day_to_keep = day_nums_to_keep.next();
-    for (i, day) in month.days.iter().enumerate() {
-        let num = (i + 1) as u32;
-        if next_day_to_keep.is_none() {
-            break;
-        }
-        let keep_day = *next_day_to_keep.unwrap();
-        if keep_day == num {
-            next_day_to_keep = day_nums_to_keep.next();
-            continue;
-        }
-        if let Some(day) = day {
-            result.push(*day);
+/// The outcome of retention for a single backup, as needed to print a
+/// `--simulate` audit table.
+struct PruneResult {
+    mark: PruneMark,
+    group: String,
+}
+
+/// Groups backups by their filename prefix (rustic's `SnapshotGroupCriterion`
+/// idea) and runs retention independently within each group, so unrelated
+/// backup sets sharing a folder don't dilute each other's daily/weekly/
+/// monthly counts.
+fn compute_prune_marks_grouped(backups: &[Backup], keep: &KeepOptions) -> HashMap<usize, PruneResult> {
+    let mut groups: HashMap<String, Vec<(usize, Date)>> = HashMap::new();
+    for (idx, backup) in backups.iter().enumerate() {
+        groups
+            .entry(group_key(&backup.name))
+            .or_default()
+            .push((idx, backup.date));
+    }
+
+    let mut results = HashMap::new();
+    for (group, indices) in &groups {
+        for (orig_idx, mark) in compute_prune_marks(indices, keep) {
+            results.insert(
+                orig_idx,
+                PruneResult {
+                    mark,
+                    group: group.clone(),
+                },
+            );
         }
     }
-    result
+    results
 }
 
-#[derive(Debug)]
-enum MonthPolicy {
-    Daily,
-    Weekly,
-    BiMonthly,
-    First,
+/// Prints a per-backup audit table: date, group, the category that kept it
+/// (if any), and the final verdict. Used by `--simulate` to let a user check
+/// a policy before running it for real.
+fn print_simulation(backups: &[Backup], results: &HashMap<usize, PruneResult>) {
+    let mut order: Vec<usize> = (0..backups.len()).collect();
+    order.sort_by(|&a, &b| backups[b].date.cmp(&backups[a].date));
+    for idx in order {
+        let date = backups[idx].date;
+        let (group, verdict, category) = match results.get(&idx) {
+            Some(PruneResult {
+                mark: PruneMark::Keep(category),
+                group,
+            }) => (group.as_str(), "Keep", *category),
+            Some(PruneResult { group, .. }) => (group.as_str(), "Remove", "-"),
+            None => ("", "Remove", "-"),
+        };
+        println!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}  group={:<20} category={:<7} {}  {}",
+            date.year,
+            date.month,
+            date.day,
+            date.hour,
+            date.minute,
+            date.second,
+            group,
+            category,
+            verdict,
+            backups[idx].name,
+        );
+    }
 }
 
-fn policy_for(cur: Date, year: u32, month: u32) -> MonthPolicy {
-    if cur.year < year {
-        return MonthPolicy::Daily;
-    }
-    // for previous years, adjust the month accordingly based on the year delta
-    // to only have to consider the month delta
-    let cur_month = if year < cur.year {
-        cur.month + (12 * (cur.year - year))
-    } else {
-        cur.month
-    };
-    if cur_month <= month {
-        return MonthPolicy::Daily;
-    }
-    if cur_month - month < 2 {
-        return MonthPolicy::Daily;
+/// The part of a backup's filename that identifies its backup set: everything
+/// before the embedded `YYYYMMDD` date (the date and the extension that
+/// follows it are both dropped). Searches the full name rather than an
+/// extension-truncated stem, so a prefix containing a `.` (e.g. a version
+/// like `v1.2-db-main-20230101.tar.gz`) doesn't get cut short.
+///
+/// Also recognizes the proxmox-style ISO-8601 format from `parse_iso8601` —
+/// the whole name is the timestamp there, so every such backup shares one
+/// group instead of becoming a singleton that retention can never prune.
+fn group_key(name: &str) -> String {
+    if parse_iso8601(name).is_some() {
+        return String::new();
     }
-    if cur_month - month < 4 {
-        return MonthPolicy::Weekly;
+    match parse_compact(name) {
+        Some((start, _end, _date)) => name[..start].to_string(),
+        None => match name.rfind('.') {
+            Some(pos) => name[..pos].to_string(),
+            None => name.to_string(),
+        },
     }
-    if cur_month - month < 9 {
-        return MonthPolicy::BiMonthly;
+}
+
+/// Decide which backups survive retention, following the restic/proxmox-backup
+/// "keep N of each period" model rather than a fixed schedule.
+///
+/// Walks `backups` newest-first once per enabled category (last/hourly/daily/
+/// weekly/monthly/yearly). Within a category, the first backup seen for a given
+/// period (e.g. a given day for `keep_daily`) is kept; once the category's
+/// count (e.g. `keep_daily`) of distinct periods has been kept, it stops
+/// looking at older backups. A
+/// backup kept by any single category is kept overall.
+fn compute_prune_marks(backups: &[(usize, Date)], keep: &KeepOptions) -> HashMap<usize, PruneMark> {
+    let mut order: Vec<usize> = (0..backups.len()).collect();
+    order.sort_by(|&a, &b| backups[b].1.cmp(&backups[a].1));
+
+    type PeriodId = fn(&Date, usize) -> String;
+
+    let mut marks = HashMap::new();
+    let categories: [(Option<u32>, &'static str, PeriodId); 6] = [
+        (keep.keep_last, "last", |_date, idx| idx.to_string()),
+        (keep.keep_hourly, "hourly", |date, _idx| {
+            format!("{:04}{:02}{:02}{:02}", date.year, date.month, date.day, date.hour)
+        }),
+        (keep.keep_daily, "daily", |date, _idx| {
+            format!("{:04}{:02}{:02}", date.year, date.month, date.day)
+        }),
+        (keep.keep_weekly, "weekly", |date, _idx| {
+            let iso_week = date.to_naive().iso_week();
+            format!("{}{:02}", iso_week.year(), iso_week.week())
+        }),
+        (keep.keep_monthly, "monthly", |date, _idx| {
+            format!("{:04}{:02}", date.year, date.month)
+        }),
+        (keep.keep_yearly, "yearly", |date, _idx| format!("{:04}", date.year)),
+    ];
+
+    for (keep_n, label, period_id) in categories {
+        let keep_n = match keep_n {
+            Some(n) if n > 0 => n,
+            _ => continue,
+        };
+        let mut seen = HashSet::new();
+        let mut count = 0;
+        for &local_idx in &order {
+            if count >= keep_n {
+                break;
+            }
+            let (orig_idx, date) = backups[local_idx];
+            if seen.insert(period_id(&date, orig_idx)) {
+                // first-wins: report the category that ran first in `categories`,
+                // not whichever one happens to match last.
+                marks.entry(orig_idx).or_insert(PruneMark::Keep(label));
+                count += 1;
+            }
+        }
     }
-    if cur_month - month < 12 {
-        return MonthPolicy::First;
+
+    for &(orig_idx, _) in backups {
+        marks.entry(orig_idx).or_insert(PruneMark::Remove);
     }
-    MonthPolicy::First
+    marks
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct Date {
     year: u32,
     month: u32,
     day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
 }
 
 impl Date {
-    fn new(year: u32, month: u32, day: u32) -> Self {
-        Self { year, month, day }
+    fn to_naive(self) -> NaiveDate {
+        NaiveDate::from_ymd_opt(self.year as i32, self.month, self.day).expect("valid backup date")
     }
 }
 
@@ -342,49 +475,218 @@ impl FromStr for Date {
     type Err = NotBackup;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // yyyymmdd.tar.gz
-        if s.len() < 11 {
-            return Err(NotBackup);
+        // proxmox-backup-style timestamp, e.g. "2019-12-05T07:55:19Z"
+        if let Some(date) = parse_iso8601(s) {
+            return Ok(date);
         }
-        let year: u32 = s[..4].parse()?;
-        let month: u32 = s[4..6].parse()?;
-        let day: u32 = s[6..8].parse()?;
-        Ok(Date { year, month, day })
+        // "20230101.tar.gz", "20230101T075519.tar.gz", "db-main-20230101.tar.gz";
+        // the time component is optional and defaults to midnight.
+        parse_compact(s).map(|(_, _, date)| date).ok_or(NotBackup)
     }
 }
 
+fn parse_iso8601(s: &str) -> Option<Date> {
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%SZ").ok()?;
+    Some(Date {
+        year: naive.year() as u32,
+        month: naive.month(),
+        day: naive.day(),
+        hour: naive.hour(),
+        minute: naive.minute(),
+        second: naive.second(),
+    })
+}
+
 impl From<ParseIntError> for NotBackup {
     fn from(_: ParseIntError) -> Self {
         NotBackup
     }
 }
 
-struct Year {
-    num: u32,
-    months: [Month; 12],
+/// Scans `s` for an embedded `YYYYMMDD` date (optionally followed by
+/// `Thhmmss`), skipping any 8-digit run that isn't calendar-valid (e.g. an
+/// invoice or serial number that happens to be 8 digits long) instead of
+/// giving up on the first match. Returns the byte range `[start, end)` of
+/// the match alongside the parsed `Date`.
+fn parse_compact(s: &str) -> Option<(usize, usize, Date)> {
+    if s.len() < 8 {
+        return None;
+    }
+    (0..=s.len() - 8).find_map(|start| {
+        let candidate = &s[start..start + 8];
+        if !candidate.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let year: u32 = candidate[..4].parse().ok()?;
+        let month: u32 = candidate[4..6].parse().ok()?;
+        let day: u32 = candidate[6..8].parse().ok()?;
+        let (hour, minute, second, end) = match s[start + 8..].strip_prefix('T') {
+            Some(rest) if rest.len() >= 6 && rest.as_bytes()[..6].iter().all(|b| b.is_ascii_digit()) => {
+                let time = &rest[..6];
+                (
+                    time[..2].parse().ok()?,
+                    time[2..4].parse().ok()?,
+                    time[4..6].parse().ok()?,
+                    start + 8 + 7,
+                )
+            }
+            _ => (0, 0, 0, start + 8),
+        };
+        if NaiveDate::from_ymd_opt(year as i32, month, day).is_none()
+            || hour > 23
+            || minute > 59
+            || second > 59
+        {
+            return None;
+        }
+        Some((
+            start,
+            end,
+            Date {
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+            },
+        ))
+    })
 }
 
-impl Year {
-    fn new(num: u32) -> Self {
-        Self {
-            num,
-            months: Default::default(),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(name: &str) -> Date {
+        name.parse().unwrap_or_else(|_| panic!("{name:?} should parse as a backup date"))
     }
-}
 
-#[derive(Default)]
-struct Month {
-    days: [Option<Day>; 31],
-}
+    fn marks(names: &[&str], keep: &KeepOptions) -> HashMap<usize, PruneMark> {
+        let backups: Vec<(usize, Date)> = names
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| (idx, date(name)))
+            .collect();
+        compute_prune_marks(&backups, keep)
+    }
 
-#[derive(Default, Clone, Copy)]
-struct Day {
-    idx: u32,
-}
+    #[test]
+    fn parse_compact_skips_invalid_run_for_a_later_valid_one() {
+        // "10000000" looks like a date (month 00 is invalid) but the real
+        // date is the 8-digit run later in the name.
+        assert!("invoice-10000000-20230101.tar.gz".parse::<Date>().is_ok());
+        let parsed = date("invoice-10000000-20230101.tar.gz");
+        assert_eq!(parsed, date("20230101.tar.gz"));
+    }
+
+    #[test]
+    fn parse_compact_rejects_name_with_no_valid_date() {
+        assert!("invoice-10000000-00000000.tar.gz".parse::<Date>().is_err());
+    }
+
+    #[test]
+    fn group_key_strips_embedded_compact_date() {
+        assert_eq!(group_key("db-main-20230101.tar.gz"), "db-main-");
+    }
+
+    #[test]
+    fn group_key_keeps_dotted_prefix_intact() {
+        // a '.' in the prefix itself (e.g. a version number) shouldn't be
+        // mistaken for the extension separator.
+        assert_eq!(group_key("v1.2-db-main-20230101.tar.gz"), "v1.2-db-main-");
+    }
+
+    #[test]
+    fn group_key_groups_iso8601_names_together() {
+        // proxmox-style snapshot names are the timestamp in full, with no
+        // distinguishing prefix, so they must all land in the same group
+        // instead of each becoming its own singleton.
+        assert_eq!(
+            group_key("2019-12-05T07:55:19Z"),
+            group_key("2020-01-01T00:00:00Z"),
+        );
+    }
+
+    #[test]
+    fn keep_daily_keeps_the_newest_backup_per_day_across_period_boundary() {
+        let keep = KeepOptions {
+            keep_daily: Some(10),
+            ..Default::default()
+        };
+        let result = marks(
+            &[
+                "20230101T235900.tar.gz", // day 1, late
+                "20230102T000100.tar.gz", // day 2, just after midnight
+                "20230102T120000.tar.gz", // day 2, later same day
+            ],
+            &keep,
+        );
+        assert_eq!(result[&0], PruneMark::Keep("daily"));
+        assert_eq!(result[&1], PruneMark::Remove); // superseded by the later backup on day 2
+        assert_eq!(result[&2], PruneMark::Keep("daily"));
+    }
+
+    #[test]
+    fn keep_weekly_respects_iso_week_boundary() {
+        let keep = KeepOptions {
+            keep_weekly: Some(10),
+            ..Default::default()
+        };
+        // 2023-01-01 is a Sunday in ISO week 52 of 2022; 2023-01-02 starts
+        // ISO week 1 of 2023.
+        let result = marks(&["20230101.tar.gz", "20230102.tar.gz"], &keep);
+        assert_eq!(result[&0], PruneMark::Keep("weekly"));
+        assert_eq!(result[&1], PruneMark::Keep("weekly"));
+    }
+
+    #[test]
+    fn keep_monthly_keeps_one_per_month() {
+        let keep = KeepOptions {
+            keep_monthly: Some(10),
+            ..Default::default()
+        };
+        let result = marks(
+            &["20230131.tar.gz", "20230201.tar.gz", "20230215.tar.gz"],
+            &keep,
+        );
+        assert_eq!(result[&0], PruneMark::Keep("monthly"));
+        assert_eq!(result[&1], PruneMark::Remove); // superseded by the later backup in February
+        assert_eq!(result[&2], PruneMark::Keep("monthly"));
+    }
+
+    #[test]
+    fn keep_yearly_keeps_one_per_year() {
+        let keep = KeepOptions {
+            keep_yearly: Some(10),
+            ..Default::default()
+        };
+        let result = marks(
+            &["20221231.tar.gz", "20230101.tar.gz", "20230601.tar.gz"],
+            &keep,
+        );
+        assert_eq!(result[&0], PruneMark::Keep("yearly"));
+        assert_eq!(result[&1], PruneMark::Remove); // superseded by the later backup in 2023
+        assert_eq!(result[&2], PruneMark::Keep("yearly"));
+    }
+
+    #[test]
+    fn first_matching_category_wins_the_reported_label() {
+        // "last" runs before "daily" in the category order, so a backup
+        // that satisfies both should report "last", not "daily".
+        let keep = KeepOptions {
+            keep_last: Some(1),
+            keep_daily: Some(1),
+            ..Default::default()
+        };
+        let result = marks(&["20230101.tar.gz"], &keep);
+        assert_eq!(result[&0], PruneMark::Keep("last"));
+    }
 
-impl Day {
-    fn new(idx: u32) -> Self {
-        Self { idx }
+    #[test]
+    fn no_retention_configured_removes_everything() {
+        let result = marks(&["20230101.tar.gz", "20230102.tar.gz"], &KeepOptions::default());
+        assert_eq!(result[&0], PruneMark::Remove);
+        assert_eq!(result[&1], PruneMark::Remove);
     }
 }